@@ -1,26 +1,109 @@
+use base64::{engine::general_purpose, Engine as _};
 use dotenv::dotenv;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env,
-    fs::File,
+    fs::{self, File},
     io::{BufReader, Write},
     path::PathBuf,
     sync::Arc,
 };
-use teloxide::{prelude::*, utils::command::BotCommands};
+use teloxide::{prelude::*, types::PhotoSize, utils::command::BotCommands};
 use tokio::sync::Mutex;
 
 #[derive(Serialize)]
 struct ChatRequest {
     model: String,
     messages: Vec<MessageRole>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct MessageRole {
     role: String,
-    content: String,
+    content: Content,
+}
+
+/// A message's content: either plain text, or (for vision-capable models) a
+/// list of text/image parts, matching the format aichat adopted for vision.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ImageUrl {
+    url: String,
+}
+
+impl Content {
+    fn text(s: impl Into<String>) -> Self {
+        Content::Text(s.into())
+    }
+
+    /// Character-equivalent size used for token estimation: text parts count
+    /// their length directly, image parts count as [`IMAGE_TOKEN_ESTIMATE`]
+    /// tokens' worth of characters so they aren't pruned as if they were free.
+    fn char_len(&self) -> usize {
+        match self {
+            Content::Text(s) => s.len(),
+            Content::Parts(parts) => parts
+                .iter()
+                .map(|p| match p {
+                    ContentPart::Text { text } => text.len(),
+                    ContentPart::ImageUrl { .. } => IMAGE_TOKEN_ESTIMATE * CHARS_PER_TOKEN,
+                })
+                .sum(),
+        }
+    }
+
+    fn has_image(&self) -> bool {
+        matches!(self, Content::Parts(parts) if parts.iter().any(|p| matches!(p, ContentPart::ImageUrl { .. })))
+    }
+
+    /// Human-readable text for transcript logging; drops image data.
+    fn preview(&self) -> String {
+        match self {
+            Content::Text(s) => s.clone(),
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|p| match p {
+                    ContentPart::Text { text } => Some(text.clone()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+
+    /// Raw base64 payloads of any `data:` image URLs, for backends (like
+    /// Ollama) that want images as a bare base64 list rather than inline
+    /// content-parts.
+    fn image_base64_payloads(&self) -> Vec<String> {
+        let Content::Parts(parts) = self else {
+            return Vec::new();
+        };
+        parts
+            .iter()
+            .filter_map(|p| match p {
+                ContentPart::ImageUrl { image_url } => {
+                    image_url.url.split_once(',').map(|(_, b64)| b64.to_string())
+                }
+                ContentPart::Text { .. } => None,
+            })
+            .collect()
+    }
 }
 
 #[derive(Deserialize)]
@@ -38,6 +121,39 @@ struct MessageContent {
     content: String,
 }
 
+#[derive(Serialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+}
+
+/// Ollama's `/api/chat` takes plain-text `content` plus a sibling `images`
+/// array of base64 payloads, unlike OpenAI's inline content-parts — so a
+/// [`MessageRole`] is converted into this shape rather than reused directly.
+#[derive(Serialize)]
+struct OllamaMessage {
+    role: String,
+    content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+impl From<&MessageRole> for OllamaMessage {
+    fn from(msg: &MessageRole) -> Self {
+        Self {
+            role: msg.role.clone(),
+            content: msg.content.preview(),
+            images: msg.content.image_base64_payloads(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OllamaChatResponse {
+    message: MessageContent,
+}
+
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase", description = "Available commands:")]
 enum Command {
@@ -49,6 +165,20 @@ enum Command {
     RemoveUser(String),
     #[command(description = "Show authorized users.")]
     ListUsers,
+    #[command(description = "Grant a user admin rights.")]
+    AddAdmin(String),
+    #[command(description = "Revoke a user's admin rights.")]
+    RemoveAdmin(String),
+    #[command(description = "Clear the current chat's conversation history.")]
+    Reset,
+    #[command(description = "Start a new conversation (alias for /reset).")]
+    New,
+    #[command(description = "Activate a named role for this chat.")]
+    Role(String),
+    #[command(description = "List available roles.")]
+    Roles,
+    #[command(description = "Turn transcript logging on or off: /save on|off")]
+    Save(String),
     #[command(description = "Show help")]
     Help,
 }
@@ -61,12 +191,45 @@ async fn main() {
     let whitelist = Arc::new(Mutex::new(WhiteList::load()));
     let whitelist_clone = Arc::clone(&whitelist);
 
+    let admins = Arc::new(Mutex::new(AdminList::load()));
+    let admins_clone = Arc::clone(&admins);
+
+    let conversations = Arc::new(Mutex::new(ConversationStore::load()));
+    let conversations_clone = Arc::clone(&conversations);
+
+    let roles = Arc::new(Mutex::new(Roles::load()));
+    let roles_clone = Arc::clone(&roles);
+
+    let config = Arc::new(Config::load());
+    let config_clone = Arc::clone(&config);
+
+    let save_mode = Arc::new(Mutex::new(SaveMode::new(config.save)));
+    let save_mode_clone = Arc::clone(&save_mode);
+
     let command_handler =
         Update::filter_message().branch(dptree::entry().filter_command::<Command>().endpoint(
             move |bot: Bot, msg: Message, cmd: Command| {
                 let whitelist = Arc::clone(&whitelist_clone);
+                let admins = Arc::clone(&admins_clone);
+                let conversations = Arc::clone(&conversations_clone);
+                let roles = Arc::clone(&roles_clone);
+                let config = Arc::clone(&config_clone);
+                let save_mode = Arc::clone(&save_mode_clone);
 
-                async move { handle_command(bot, msg, cmd, whitelist).await }
+                async move {
+                    handle_command(
+                        bot,
+                        msg,
+                        cmd,
+                        whitelist,
+                        admins,
+                        conversations,
+                        roles,
+                        config,
+                        save_mode,
+                    )
+                    .await
+                }
             },
         ));
 
@@ -86,7 +249,14 @@ async fn main() {
     let handler = command_handler.branch(free_text_handler);
 
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![whitelist])
+        .dependencies(dptree::deps![
+            whitelist,
+            admins,
+            conversations,
+            roles,
+            config,
+            save_mode
+        ])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
@@ -100,10 +270,19 @@ async fn handle_command(
     msg: Message,
     cmd: Command,
     whitelist: Arc<Mutex<WhiteList>>,
+    admins: Arc<Mutex<AdminList>>,
+    conversations: Arc<Mutex<ConversationStore>>,
+    roles: Arc<Mutex<Roles>>,
+    config: Arc<Config>,
+    save_mode: Arc<Mutex<SaveMode>>,
 ) -> ResponseResult<()> {
     let username = msg.from().and_then(|u| u.username.clone());
 
-    let is_admin = matches!(username.as_deref(), Some("ksander314"));
+    let is_admin = if let Some(u) = username.as_deref() {
+        admins.lock().await.is_admin(u)
+    } else {
+        false
+    };
 
     let is_allowed = if let Some(u) = username.as_deref() {
         let wl = whitelist.lock().await;
@@ -139,12 +318,109 @@ async fn handle_command(
             bot.send_message(msg.chat.id, format!("👥 Whitelisted:\n@{}", list))
                 .await?;
         }
+        Command::AddAdmin(user) if is_admin => {
+            let mut al = admins.lock().await;
+            if al.add_admin(&user) {
+                bot.send_message(msg.chat.id, format!("✅ @{user} is now an admin"))
+                    .await?;
+            } else {
+                bot.send_message(msg.chat.id, format!("ℹ️ @{user} is already an admin"))
+                    .await?;
+            }
+        }
+        Command::RemoveAdmin(user) if is_admin => {
+            let mut al = admins.lock().await;
+            match al.remove_admin(&user) {
+                RemoveAdminResult::Removed => {
+                    bot.send_message(msg.chat.id, format!("🗑 Revoked admin rights for @{user}"))
+                        .await?;
+                }
+                RemoveAdminResult::NotAnAdmin => {
+                    bot.send_message(msg.chat.id, format!("⚠️ @{user} was not an admin"))
+                        .await?;
+                }
+                RemoveAdminResult::LastAdmin => {
+                    bot.send_message(
+                        msg.chat.id,
+                        "⚠️ Can't remove the last remaining admin",
+                    )
+                    .await?;
+                }
+            }
+        }
         Command::Ask(q) if is_allowed => {
-            let reply = ask_gpt(&q)
+            let mut conv = conversations.lock().await;
+            conv.push_user(msg.chat.id, Content::text(&q));
+            let history = conv.history(msg.chat.id);
+            drop(conv);
+
+            let system_prompt = roles.lock().await.active_prompt(msg.chat.id);
+            let messages = with_system_prompt(system_prompt, history);
+
+            let reply = ask_gpt(&config, &config.model, messages)
                 .await
                 .unwrap_or("Error contacting OpenAI.".to_string());
-            bot.send_message(msg.chat.id, reply).await?;
+
+            let mut conv = conversations.lock().await;
+            conv.push_assistant(msg.chat.id, &reply);
+            drop(conv);
+
+            if save_mode.lock().await.is_enabled(msg.chat.id) {
+                let who = username.as_deref().unwrap_or("unknown");
+                append_transcript(who, &q, &reply);
+            }
+
+            send_reply(&bot, msg.chat.id, &reply).await?;
+        }
+        Command::Reset if is_allowed => {
+            let mut conv = conversations.lock().await;
+            conv.reset(msg.chat.id);
+            bot.send_message(msg.chat.id, "🧹 Conversation history cleared.")
+                .await?;
+        }
+        Command::New if is_allowed => {
+            let mut conv = conversations.lock().await;
+            conv.reset(msg.chat.id);
+            bot.send_message(msg.chat.id, "🆕 Started a new conversation.")
+                .await?;
+        }
+        Command::Role(name) if is_allowed => {
+            let mut r = roles.lock().await;
+            if r.activate(msg.chat.id, &name) {
+                bot.send_message(msg.chat.id, format!("🎭 Role set to \"{name}\""))
+                    .await?;
+            } else {
+                bot.send_message(msg.chat.id, format!("⚠️ No such role: \"{name}\""))
+                    .await?;
+            }
         }
+        Command::Roles if is_allowed => {
+            let r = roles.lock().await;
+            let list = r.list();
+            if list.is_empty() {
+                bot.send_message(msg.chat.id, "No roles configured.")
+                    .await?;
+            } else {
+                bot.send_message(msg.chat.id, format!("🎭 Available roles:\n{}", list.join("\n")))
+                    .await?;
+            }
+        }
+        Command::Save(mode) if is_allowed => match mode.to_lowercase().as_str() {
+            "on" => {
+                save_mode.lock().await.set(msg.chat.id, true);
+                bot.send_message(msg.chat.id, "💾 Transcript logging enabled.")
+                    .await?;
+            }
+            "off" => {
+                save_mode.lock().await.set(msg.chat.id, false);
+                bot.send_message(msg.chat.id, "💾 Transcript logging disabled.")
+                    .await?;
+            }
+            _ => {
+                bot.send_message(msg.chat.id, "Usage: /save on|off")
+                    .await?;
+            }
+        },
         Command::Help => {
             bot.send_message(msg.chat.id, Command::descriptions().to_string())
                 .await?;
@@ -160,6 +436,10 @@ async fn handle_free_text(
     bot: Bot,
     msg: Message,
     whitelist: Arc<Mutex<WhiteList>>,
+    conversations: Arc<Mutex<ConversationStore>>,
+    roles: Arc<Mutex<Roles>>,
+    config: Arc<Config>,
+    save_mode: Arc<Mutex<SaveMode>>,
 ) -> ResponseResult<()> {
     let username = msg.from().and_then(|u| u.username.as_deref());
     let is_allowed = if let Some(u) = username {
@@ -174,30 +454,290 @@ async fn handle_free_text(
         return Ok(());
     };
 
-    if let Some(text) = msg.text() {
-        let reply = ask_gpt(text)
+    let user_content = if let Some(photo) = msg.photo().and_then(|sizes| sizes.last()) {
+        match download_image_data_url(&bot, photo).await {
+            Ok(url) => Some(Content::Parts(vec![
+                ContentPart::Text {
+                    text: msg.caption().unwrap_or_default().to_string(),
+                },
+                ContentPart::ImageUrl {
+                    image_url: ImageUrl { url },
+                },
+            ])),
+            Err(_) => {
+                bot.send_message(msg.chat.id, "⚠️ Failed to download photo.")
+                    .await?;
+                None
+            }
+        }
+    } else {
+        msg.text().map(Content::text)
+    };
+
+    if let Some(user_content) = user_content {
+        let model = if user_content.has_image() {
+            config.vision_model.as_deref().unwrap_or(&config.model)
+        } else {
+            &config.model
+        }
+        .to_string();
+        let preview = user_content.preview();
+
+        let mut conv = conversations.lock().await;
+        conv.push_user(msg.chat.id, user_content);
+        let history = conv.history(msg.chat.id);
+        drop(conv);
+
+        let system_prompt = roles.lock().await.active_prompt(msg.chat.id);
+        let messages = with_system_prompt(system_prompt, history);
+
+        let reply = ask_gpt(&config, &model, messages)
             .await
             .unwrap_or("Error contacting OpenAI.".to_string());
-        bot.send_message(msg.chat.id, reply).await?;
+
+        let mut conv = conversations.lock().await;
+        conv.push_assistant(msg.chat.id, &reply);
+        drop(conv);
+
+        if save_mode.lock().await.is_enabled(msg.chat.id) {
+            let who = username.unwrap_or("unknown");
+            append_transcript(who, &preview, &reply);
+        }
+
+        send_reply(&bot, msg.chat.id, &reply).await?;
+    }
+    Ok(())
+}
+
+/// Downloads the given photo via the bot's file API and returns it as a
+/// base64 `data:` URL suitable for a vision-capable model's `image_url` part.
+async fn download_image_data_url(
+    bot: &Bot,
+    photo: &PhotoSize,
+) -> Result<String, teloxide::RequestError> {
+    let file = bot.get_file(&photo.file.id).await?;
+    let mut buf = Vec::new();
+    bot.download_file(&file.path, &mut buf).await?;
+    Ok(format!(
+        "data:image/jpeg;base64,{}",
+        general_purpose::STANDARD.encode(buf)
+    ))
+}
+
+const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+
+/// Sends `text` as one or more messages, splitting it so no chunk exceeds
+/// Telegram's 4096-character limit.
+async fn send_reply(bot: &Bot, chat_id: ChatId, text: &str) -> ResponseResult<()> {
+    for chunk in split_message(text, TELEGRAM_MESSAGE_LIMIT) {
+        bot.send_message(chat_id, chunk).await?;
     }
     Ok(())
 }
 
-async fn ask_gpt(prompt: &str) -> Result<String, reqwest::Error> {
+/// `"```\n"`, the overhead of closing or re-opening a fence around a split.
+const FENCE_MARKER_LEN: usize = 4;
+
+/// Splits `text` into chunks no longer than `limit`, preferring to break on
+/// newline boundaries. A fenced code block that straddles a chunk boundary is
+/// closed at the end of one chunk and re-opened at the start of the next, so
+/// Markdown rendering stays intact. A single line too long to fit in a chunk
+/// on its own (inside or outside a code block) is hard-split at a character
+/// boundary so no chunk ever exceeds `limit`.
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    if text.len() <= limit {
+        return vec![text.to_string()];
+    }
+
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut in_code_block = false;
+
+    for line in text.split('\n') {
+        let toggles_fence = line.trim_start().starts_with("```");
+        let addition = line.len() + 1;
+        // If this chunk is still inside a code block when it's flushed, the
+        // closing fence gets appended on top of whatever's already in
+        // `current`, so reserve room for it before deciding it still fits.
+        // An opening fence line puts the chunk inside a code block, and a
+        // closing one takes it out, so look at the state after this line.
+        let in_code_block_after = in_code_block != toggles_fence;
+        let fence_close_cost = if in_code_block_after { FENCE_MARKER_LEN } else { 0 };
+
+        if current_has_content(&current, in_code_block)
+            && current.len() + addition + fence_close_cost > limit
+        {
+            flush_chunk(&mut current, &mut chunks, in_code_block);
+        }
+
+        // A fence re-open/close costs FENCE_MARKER_LEN on either side of a
+        // chunk that's still inside a code block, plus the line's own "\n".
+        let fence_overhead = if in_code_block { 2 * FENCE_MARKER_LEN } else { 0 };
+        if line.len() + 1 + fence_overhead > limit {
+            let max_piece_len = limit.saturating_sub(1 + fence_overhead).max(1);
+            for piece in hard_split_line(line, max_piece_len) {
+                if current_has_content(&current, in_code_block) {
+                    flush_chunk(&mut current, &mut chunks, in_code_block);
+                }
+                current.push_str(piece);
+                current.push('\n');
+            }
+        } else {
+            current.push_str(line);
+            current.push('\n');
+        }
+
+        if toggles_fence {
+            in_code_block = !in_code_block;
+        }
+    }
+
+    if current_has_content(&current, in_code_block) {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+/// Whether `current` holds anything beyond a freshly reopened fence marker,
+/// i.e. whether flushing it now would emit a real chunk rather than a bare
+/// `` ```\n```\n `` pair.
+fn current_has_content(current: &str, in_code_block: bool) -> bool {
+    current.len() > if in_code_block { FENCE_MARKER_LEN } else { 0 }
+}
+
+/// Closes the fence (if inside a code block), pushes `current` as a finished
+/// chunk, and re-opens the fence in the now-empty `current`.
+fn flush_chunk(current: &mut String, chunks: &mut Vec<String>, in_code_block: bool) {
+    if in_code_block {
+        current.push_str("```\n");
+    }
+    chunks.push(std::mem::take(current));
+    if in_code_block {
+        current.push_str("```\n");
+    }
+}
+
+/// Splits `line` into pieces of at most `max_len` bytes, each ending on a
+/// valid UTF-8 character boundary. A single character wider than `max_len`
+/// bytes is kept whole as its own piece.
+fn hard_split_line(line: &str, max_len: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while start < line.len() {
+        let mut end = (start + max_len).min(line.len());
+        while end > start && !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == start {
+            end = start + 1;
+            while end < line.len() && !line.is_char_boundary(end) {
+                end += 1;
+            }
+        }
+        pieces.push(&line[start..end]);
+        start = end;
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod split_message_tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_a_single_chunk() {
+        let chunks = split_message("hello", 4096);
+        assert_eq!(chunks, vec!["hello".to_string()]);
+    }
+
+    #[test]
+    fn splits_on_newline_boundaries_under_the_limit() {
+        let text = format!("{}\n{}", "a".repeat(30), "b".repeat(30));
+        let chunks = split_message(&text, 40);
+        assert!(chunks.iter().all(|c| c.len() <= 40));
+        assert_eq!(chunks.len(), 2);
+    }
+
+    #[test]
+    fn hard_splits_a_single_line_longer_than_the_limit() {
+        let text = "x".repeat(5000);
+        let chunks = split_message(&text, 4096);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.len() <= 4096));
+        assert_eq!(chunks.concat().replace('\n', ""), text);
+    }
+
+    #[test]
+    fn hard_splits_a_long_line_inside_a_fenced_code_block() {
+        let text = format!("```\n{}\n```", "y".repeat(5000));
+        let chunks = split_message(&text, 4096);
+        assert!(chunks.iter().all(|c| c.len() <= 4096));
+    }
+
+    #[test]
+    fn no_chunk_is_blank_or_fence_only() {
+        let text = format!("```\n{}\n```", "y".repeat(5000));
+        let chunks = split_message(&text, 4096);
+        assert!(chunks
+            .iter()
+            .all(|c| !c.is_empty() && c.as_str() != "```\n```\n"));
+    }
+
+    #[test]
+    fn fenced_code_block_chunk_never_overflows_the_limit_for_closing_fence() {
+        let body: String = (0..600).map(|i| format!("line {i}\n")).collect();
+        let text = format!("some prose before the block\n```\n{body}```\nsome prose after");
+        let chunks = split_message(&text, 4096);
+        assert!(chunks.iter().all(|c| c.len() <= 4096));
+    }
+}
+
+/// Prepends a `system` message built from an active role's prompt, if any.
+fn with_system_prompt(prompt: Option<String>, history: Vec<MessageRole>) -> Vec<MessageRole> {
+    match prompt {
+        Some(content) => {
+            let mut messages = Vec::with_capacity(history.len() + 1);
+            messages.push(MessageRole {
+                role: "system".to_string(),
+                content: Content::text(content),
+            });
+            messages.extend(history);
+            messages
+        }
+        None => history,
+    }
+}
+
+async fn ask_gpt(
+    config: &Config,
+    model: &str,
+    messages: Vec<MessageRole>,
+) -> Result<String, reqwest::Error> {
+    match config.provider {
+        Provider::OpenAi => ask_openai(config, model, messages).await,
+        Provider::Ollama => ask_ollama(config, model, messages).await,
+    }
+}
+
+async fn ask_openai(
+    config: &Config,
+    model: &str,
+    messages: Vec<MessageRole>,
+) -> Result<String, reqwest::Error> {
     let api_key = env::var("TWM_OPENAI_API_KEY").expect("TWM_OPENAI_API_KEY not set");
 
-    let client = reqwest::Client::new();
+    let client = build_client(config)?;
 
     let body = ChatRequest {
-        model: "gpt-4.1".to_string(),
-        messages: vec![MessageRole {
-            role: "user".to_string(),
-            content: prompt.to_string(),
-        }],
+        model: model.to_string(),
+        messages,
+        temperature: config.temperature,
     };
 
+    let url = format!("{}/chat/completions", config.base_url);
     let response = client
-        .post("https://api.openai.com/v1/chat/completions")
+        .post(url)
         .bearer_auth(api_key)
         .json(&body)
         .send()
@@ -208,12 +748,103 @@ async fn ask_gpt(prompt: &str) -> Result<String, reqwest::Error> {
     Ok(result.choices[0].message.content.clone())
 }
 
+const OLLAMA_URL: &str = "http://localhost:11434/api/chat";
+
+async fn ask_ollama(
+    config: &Config,
+    model: &str,
+    messages: Vec<MessageRole>,
+) -> Result<String, reqwest::Error> {
+    let client = build_client(config)?;
+
+    let body = OllamaChatRequest {
+        model: model.to_string(),
+        messages: messages.iter().map(OllamaMessage::from).collect(),
+        stream: false,
+    };
+
+    let response = client.post(OLLAMA_URL).json(&body).send().await?;
+
+    let result: OllamaChatResponse = response.json().await?;
+
+    Ok(result.message.content)
+}
+
+fn build_client(config: &Config) -> Result<reqwest::Client, reqwest::Error> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    builder.build()
+}
+
 const WHITELIST_FILE: &str = "whitelist.json";
 fn get_config_path(file_name: &str) -> PathBuf {
     let config_dir = env::var("TWM_CONFIG_DIR").unwrap_or_else(|_| ".".to_string());
     PathBuf::from(config_dir).join(file_name)
 }
 
+const CONFIG_FILE: &str = "config.yaml";
+
+fn default_base_url() -> String {
+    "https://api.openai.com/v1".to_string()
+}
+
+/// Which backend `ask_gpt` talks to.
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Provider {
+    #[default]
+    OpenAi,
+    Ollama,
+}
+
+/// Bot-wide settings, mirroring aichat's `config.yaml`. Lets users point the
+/// bot at any OpenAI-compatible endpoint (or a local Ollama server) and tune
+/// sampling without recompiling.
+#[derive(Deserialize)]
+struct Config {
+    model: String,
+    /// Model used instead of `model` for messages that include an image.
+    vision_model: Option<String>,
+    temperature: Option<f64>,
+    #[serde(default = "default_base_url")]
+    base_url: String,
+    proxy: Option<String>,
+    #[serde(default)]
+    provider: Provider,
+    /// Default for whether exchanges are appended to `messages.md`. Can be
+    /// overridden per chat at runtime with `/save on|off`.
+    #[serde(default)]
+    save: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            model: "gpt-4.1".to_string(),
+            vision_model: None,
+            temperature: None,
+            base_url: default_base_url(),
+            proxy: None,
+            provider: Provider::default(),
+            save: false,
+        }
+    }
+}
+
+impl Config {
+    fn load() -> Self {
+        let path = get_config_path(CONFIG_FILE);
+        if path.exists() {
+            let contents = fs::read_to_string(&path).expect("Failed to read config file");
+            serde_yaml::from_str(&contents).expect("Failed to parse config file")
+        } else {
+            Self::default()
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Default)]
 struct WhiteList {
     users: HashSet<String>,
@@ -263,3 +894,297 @@ impl WhiteList {
         self.users.iter().cloned().collect()
     }
 }
+
+const ADMINS_FILE: &str = "admins.json";
+
+/// Usernames allowed to manage the whitelist and the admin list itself,
+/// persisted like [`WhiteList`]. Seeded with the original maintainer handle
+/// on first run so existing deployments keep working, but now editable with
+/// `/addadmin` and `/removeadmin` instead of being baked into the binary.
+#[derive(Serialize, Deserialize)]
+struct AdminList {
+    admins: HashSet<String>,
+}
+
+impl Default for AdminList {
+    fn default() -> Self {
+        Self {
+            admins: HashSet::from(["ksander314".to_string()]),
+        }
+    }
+}
+
+/// Outcome of [`AdminList::remove_admin`]; distinguishes "not an admin" from
+/// "would have emptied the admin list" so callers can give the operator an
+/// accurate reason instead of a generic failure.
+enum RemoveAdminResult {
+    Removed,
+    NotAnAdmin,
+    LastAdmin,
+}
+
+impl AdminList {
+    fn load() -> Self {
+        let path = get_config_path(ADMINS_FILE);
+        if path.exists() {
+            let file = File::open(&path).expect("Failed to open admins file");
+            let reader = BufReader::new(file);
+            serde_json::from_reader(reader).unwrap_or_default()
+        } else {
+            Self::default()
+        }
+    }
+
+    fn save(&self) {
+        let path = get_config_path(ADMINS_FILE);
+        let json = serde_json::to_string_pretty(self).expect("Failed to serialize admins");
+        let mut file = File::create(path).expect("Failed to create admins file");
+        file.write_all(json.as_bytes())
+            .expect("Failed to write admins file");
+    }
+
+    fn remove_admin(&mut self, username: &str) -> RemoveAdminResult {
+        if !self.admins.contains(username) {
+            return RemoveAdminResult::NotAnAdmin;
+        }
+        if self.admins.len() <= 1 {
+            return RemoveAdminResult::LastAdmin;
+        }
+        self.admins.remove(username);
+        self.save();
+        RemoveAdminResult::Removed
+    }
+
+    fn add_admin(&mut self, username: &str) -> bool {
+        let added = self.admins.insert(username.to_string());
+        if added {
+            self.save();
+        }
+        added
+    }
+
+    fn is_admin(&self, username: &str) -> bool {
+        self.admins.contains(username)
+    }
+}
+
+const ROLES_FILE: &str = "roles.json";
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RoleDef {
+    name: String,
+    prompt: String,
+}
+
+/// Named system prompts (à la aichat's roles) that a chat can switch into
+/// with `/role <name>` to change the bot's persona without retyping
+/// instructions every time.
+#[derive(Default)]
+struct Roles {
+    defs: Vec<RoleDef>,
+    active: HashMap<ChatId, String>,
+}
+
+impl Roles {
+    fn load() -> Self {
+        let path = get_config_path(ROLES_FILE);
+        let defs = if path.exists() {
+            let file = File::open(&path).expect("Failed to open roles file");
+            let reader = BufReader::new(file);
+            serde_json::from_reader(reader).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            defs,
+            active: HashMap::new(),
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&RoleDef> {
+        self.defs.iter().find(|r| r.name == name)
+    }
+
+    fn activate(&mut self, chat_id: ChatId, name: &str) -> bool {
+        if self.find(name).is_some() {
+            self.active.insert(chat_id, name.to_string());
+            true
+        } else {
+            false
+        }
+    }
+
+    fn active_prompt(&self, chat_id: ChatId) -> Option<String> {
+        let name = self.active.get(&chat_id)?;
+        self.find(name).map(|r| r.prompt.clone())
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.defs.iter().map(|r| r.name.clone()).collect()
+    }
+}
+
+// Conservative heuristic: ~4 characters per token. Good enough for budget
+// pruning, not meant to match the real tokenizer exactly.
+const CHARS_PER_TOKEN: usize = 4;
+// Flat per-image token estimate (roughly what a single default-resolution
+// vision tile costs). Images vary with resolution/tiling, but this keeps
+// a photo from being pruned as if it were free.
+const IMAGE_TOKEN_ESTIMATE: usize = 800;
+const DEFAULT_MAX_TOKENS: usize = 4000;
+const HISTORY_DIR: &str = "history";
+
+fn estimate_tokens(msg: &MessageRole) -> usize {
+    msg.content.char_len() / CHARS_PER_TOKEN
+}
+
+/// Per-chat conversation history with a token-budget pruning pass, persisted
+/// as one JSON file per chat under `TWM_CONFIG_DIR/history/`.
+#[derive(Default)]
+struct ConversationStore {
+    chats: HashMap<ChatId, Vec<MessageRole>>,
+    max_tokens: usize,
+}
+
+impl ConversationStore {
+    fn load() -> Self {
+        let max_tokens = env::var("TWM_MAX_TOKENS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_TOKENS);
+
+        let mut chats = HashMap::new();
+        let dir = get_config_path(HISTORY_DIR);
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+                let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+                    continue;
+                };
+                let Ok(id) = stem.parse::<i64>() else {
+                    continue;
+                };
+                if let Ok(file) = File::open(&path) {
+                    let reader = BufReader::new(file);
+                    if let Ok(history) = serde_json::from_reader::<_, Vec<MessageRole>>(reader) {
+                        chats.insert(ChatId(id), history);
+                    }
+                }
+            }
+        }
+
+        Self { chats, max_tokens }
+    }
+
+    fn history_path(chat_id: ChatId) -> PathBuf {
+        get_config_path(&format!("{HISTORY_DIR}/{}.json", chat_id.0))
+    }
+
+    fn save_chat(&self, chat_id: ChatId) {
+        let dir = get_config_path(HISTORY_DIR);
+        fs::create_dir_all(&dir).expect("Failed to create history dir");
+
+        let empty = Vec::new();
+        let history = self.chats.get(&chat_id).unwrap_or(&empty);
+        let json = serde_json::to_string_pretty(history).expect("Failed to serialize history");
+        let mut file =
+            File::create(Self::history_path(chat_id)).expect("Failed to create history file");
+        file.write_all(json.as_bytes())
+            .expect("Failed to write history file");
+    }
+
+    fn prune(&mut self, chat_id: ChatId) {
+        let Some(history) = self.chats.get_mut(&chat_id) else {
+            return;
+        };
+        let mut total: usize = history.iter().map(estimate_tokens).sum();
+        while total > self.max_tokens {
+            let Some(idx) = history.iter().position(|m| m.role != "system") else {
+                break;
+            };
+            total -= estimate_tokens(&history[idx]);
+            history.remove(idx);
+        }
+    }
+
+    fn push_user(&mut self, chat_id: ChatId, content: Content) {
+        self.chats.entry(chat_id).or_default().push(MessageRole {
+            role: "user".to_string(),
+            content,
+        });
+        self.prune(chat_id);
+        self.save_chat(chat_id);
+    }
+
+    fn push_assistant(&mut self, chat_id: ChatId, content: &str) {
+        self.chats.entry(chat_id).or_default().push(MessageRole {
+            role: "assistant".to_string(),
+            content: Content::text(content),
+        });
+        self.prune(chat_id);
+        self.save_chat(chat_id);
+    }
+
+    fn history(&self, chat_id: ChatId) -> Vec<MessageRole> {
+        self.chats.get(&chat_id).cloned().unwrap_or_default()
+    }
+
+    fn reset(&mut self, chat_id: ChatId) {
+        self.chats.remove(&chat_id);
+        let path = Self::history_path(chat_id);
+        if path.exists() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+const MESSAGES_LOG_FILE: &str = "messages.md";
+
+/// Per-chat override of [`Config::save`], toggled at runtime with `/save on|off`.
+#[derive(Default)]
+struct SaveMode {
+    default_enabled: bool,
+    overrides: HashMap<ChatId, bool>,
+}
+
+impl SaveMode {
+    fn new(default_enabled: bool) -> Self {
+        Self {
+            default_enabled,
+            overrides: HashMap::new(),
+        }
+    }
+
+    fn is_enabled(&self, chat_id: ChatId) -> bool {
+        self.overrides
+            .get(&chat_id)
+            .copied()
+            .unwrap_or(self.default_enabled)
+    }
+
+    fn set(&mut self, chat_id: ChatId, enabled: bool) {
+        self.overrides.insert(chat_id, enabled);
+    }
+}
+
+/// Appends one exchange to `messages.md` as aichat does, so admins can audit
+/// usage and users can review past answers.
+fn append_transcript(username: &str, prompt: &str, reply: &str) {
+    let path = get_config_path(MESSAGES_LOG_FILE);
+    let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    let entry = format!(
+        "## {timestamp} — @{username}\n\n**Prompt:**\n\n{prompt}\n\n**Reply:**\n\n{reply}\n\n"
+    );
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .expect("Failed to open messages log");
+    file.write_all(entry.as_bytes())
+        .expect("Failed to write messages log");
+}